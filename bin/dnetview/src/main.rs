@@ -34,6 +34,10 @@ use dnetview::{
     view::{IdListView, InfoListView, View},
 };
 
+// JSON-RPC 2.0 reserved error code for an unrecognized method, used to
+// detect nodes that don't support `dnet.subscribe_events` yet.
+const METHOD_NOT_FOUND: i64 = -32601;
+
 struct DNetView {
     url: Url,
     name: String,
@@ -81,6 +85,67 @@ impl DNetView {
         let req = jsonrpc::request(json!("get_info"), json!([]));
         Ok(self.request(req).await?)
     }
+
+    // --> {"jsonrpc": "2.0", "method": "dnet.subscribe_events", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "method": "dnet.subscribe_events", "params": {`event`}}
+    // <-- {"jsonrpc": "2.0", "method": "dnet.subscribe_events", "params": {`event`}}
+    // ...
+    //
+    /// Open a long-lived connection and subscribe to `dnet.subscribe_events`,
+    /// forwarding each pushed event into the shared `Model` as soon as it
+    /// arrives. Returns `Err` if the node doesn't understand the
+    /// subscription method at all, so the caller can fall back to `poll`.
+    ///
+    /// `jsonrpc::send_request` only models a single request/response, so
+    /// this opens its own TCP connection and frames newline-delimited JSON
+    /// over it directly rather than inventing a method on `jsonrpc` that
+    /// isn't known to exist.
+    async fn subscribe(&self, model: Arc<Model>) -> Result<()> {
+        use async_std::{
+            io::{prelude::*, BufReader},
+            net::TcpStream,
+        };
+
+        let host = self
+            .url
+            .host_str()
+            .ok_or_else(|| Error::JsonRpcError("node url has no host".to_string()))?;
+        let port = self
+            .url
+            .port_or_known_default()
+            .ok_or_else(|| Error::JsonRpcError("node url has no port".to_string()))?;
+
+        let stream = TcpStream::connect((host, port)).await?;
+        let mut writer = stream.clone();
+        let mut lines = BufReader::new(stream).lines();
+
+        let req = jsonrpc::request(json!("dnet.subscribe_events"), json!([]));
+        writer.write_all(format!("{}\n", json!(req)).as_bytes()).await?;
+
+        while let Some(line) = lines.next().await {
+            let reply: JsonResult = serde_json::from_str(&line?)?;
+
+            match reply {
+                JsonResult::Notif(n) => {
+                    debug!(target: "RPC", "<-- {}", serde_json::to_string(&n)?);
+                    if let Some(event) = n.params.as_object() {
+                        parse_data(event, self, model.clone()).await?;
+                    }
+                }
+
+                JsonResult::Err(e) if e.error.code == METHOD_NOT_FOUND => {
+                    debug!(target: "RPC", "node does not support subscriptions: {}", e.error.message);
+                    return Err(Error::JsonRpcError(e.error.message.to_string()))
+                }
+
+                // Anything else on a subscription channel is unexpected,
+                // but not fatal to the subscription itself.
+                _ => debug!(target: "RPC", "unexpected reply on subscription channel"),
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[async_std::main]
@@ -135,11 +200,25 @@ async fn main() -> Result<()> {
 async fn run_rpc(config: &DnvConfig, ex: Arc<Executor<'_>>, model: Arc<Model>) -> Result<()> {
     for node in config.nodes.clone() {
         let client = DNetView::new(Url::parse(&node.rpc_url)?, node.name);
-        ex.spawn(poll(client, model.clone())).detach();
+        ex.spawn(run_node(client, model.clone())).detach();
     }
     Ok(())
 }
 
+/// Keep a single node's entry in the model up to date: prefer the
+/// push-based subscription, and fall back to polling `get_info` on a fixed
+/// interval once the subscription ends for any reason: the node not
+/// supporting it, a connection or parse error, or the node closing it.
+async fn run_node(client: DNetView, model: Arc<Model>) {
+    if let Err(e) = client.subscribe(model.clone()).await {
+        debug!(target: "RPC", "subscribe failed ({}), falling back to polling", e);
+    }
+
+    if let Err(e) = poll(client, model).await {
+        debug!(target: "RPC", "poll loop for node exited: {}", e);
+    }
+}
+
 async fn poll(client: DNetView, model: Arc<Model>) -> Result<()> {
     loop {
         let reply = client.get_info().await?;
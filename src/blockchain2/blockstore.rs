@@ -1,60 +1,252 @@
-use sled::Batch;
+// `block_stream` below needs `async-stream` and `futures` as dependencies
+// of this crate; this tree has no Cargo.toml for either to be added to, so
+// that's left for whoever carries this patch into a real checkout.
+use async_stream::stream;
+use futures::Stream;
+use sled::{transaction::TransactionError, Transactional};
 
 use crate::{
     consensus2::{util::Timestamp, Block},
-    util::serial::{deserialize, serialize},
-    Result,
+    tx::Transaction,
+    util::serial::{deserialize, serialize, SerialDecodable, SerialEncodable},
+    Error, Result,
 };
 
 const SLED_BLOCK_TREE: &[u8] = b"_blocks";
+const SLED_BLOCK_ORDER_TREE: &[u8] = b"_block_order";
+const SLED_TX_LOCATION_TREE: &[u8] = b"_tx_location";
 
-pub struct BlockStore(sled::Tree);
+/// Key pointing at the genesis block's hash in the order tree.
+const GENESIS_KEY: &[u8] = &[0xff, b'g'];
+/// Key pointing at the current best/tip block's hash in the order tree.
+const TIP_KEY: &[u8] = &[0xff, b't'];
+/// Key holding the height of the block `TIP_KEY` points at, so the tip can
+/// be compared against an incoming block's height without a block fetch.
+const TIP_HEIGHT_KEY: &[u8] = &[0xff, b'h'];
+
+pub struct BlockStore {
+    /// Main block tree, storing the blockhash -> block pairs.
+    main: sled::Tree,
+    /// Block order tree, storing the height -> blockhash index, together
+    /// with the genesis and tip pointers.
+    order: sled::Tree,
+    /// Transaction location tree, storing the transaction hash -> location
+    /// of the transaction inside a stored block.
+    tx_location: sled::Tree,
+}
+
+/// Where a transaction lives inside the blockstore: which block contains
+/// it, and at what index.
+#[derive(Debug, Clone, Copy, SerialEncodable, SerialDecodable)]
+pub struct TransactionLocation {
+    /// Hash of the block containing the transaction
+    pub block: blake3::Hash,
+    /// Index of the transaction inside the block
+    pub index: u32,
+}
 
 impl BlockStore {
     /// Opens a new or existing `BlockStore` on the given sled database.
     pub fn new(db: &sled::Db, genesis_ts: Timestamp, genesis_data: blake3::Hash) -> Result<Self> {
-        let tree = db.open_tree(SLED_BLOCK_TREE)?;
-        let store = Self(tree);
+        let main = db.open_tree(SLED_BLOCK_TREE)?;
+        let order = db.open_tree(SLED_BLOCK_ORDER_TREE)?;
+        let tx_location = db.open_tree(SLED_TX_LOCATION_TREE)?;
+        let store = Self { main, order, tx_location };
 
         // In case the store is empty, create the genesis block.
-        if store.0.is_empty() {
+        if store.main.is_empty() {
             store.insert(&[Block::genesis_block(genesis_ts, genesis_data)])?;
         }
 
         Ok(store)
     }
 
-    /// Insert a slice of [`Block`] into the blockstore. With sled, the
-    /// operation is done as a batch.
+    /// Insert a slice of [`Block`] into the blockstore.
     /// The blocks are hashed with BLAKE3 and this blockhash is used as
-    /// the key, while value is the serialized block itself.
+    /// the key, while value is the serialized block itself. Each block's
+    /// height is indexed in the order tree, and each contained transaction
+    /// is indexed into the `_tx_location` tree, all inside the same sled
+    /// transaction as the block write itself so none of the indexes can
+    /// ever diverge from the block tree, even on a crash or panic
+    /// mid-insert. The genesis pointer is set on the first-ever block, and
+    /// the tip pointer only ever advances to a block whose height exceeds
+    /// the currently stored tip's, so writing a side-fork's blocks here
+    /// (e.g. while evaluating a candidate via `tree_route`) cannot clobber
+    /// the real tip.
     pub fn insert(&self, blocks: &[Block]) -> Result<Vec<blake3::Hash>> {
         let mut ret = Vec::with_capacity(blocks.len());
-        let mut batch = Batch::default();
-        for i in blocks {
-            let serialized = serialize(i);
-            let blockhash = blake3::hash(&serialized);
-            batch.insert(blockhash.as_bytes(), serialized);
-            ret.push(blockhash);
-        }
 
-        self.0.apply_batch(batch)?;
+        let txn_result: std::result::Result<(), TransactionError<()>> = (
+            &self.main,
+            &self.order,
+            &self.tx_location,
+        )
+            .transaction(|(main, order, tx_location)| {
+                ret.clear();
+
+                let mut tip_height = match order.get(TIP_HEIGHT_KEY)? {
+                    Some(found) => Some(u64::from_be_bytes(found.as_ref().try_into().unwrap())),
+                    None => None,
+                };
+
+                for block in blocks {
+                    let serialized = serialize(block);
+                    let blockhash = blake3::hash(&serialized);
+                    main.insert(blockhash.as_bytes(), serialized)?;
+
+                    order.insert(&block.height.to_be_bytes(), blockhash.as_bytes())?;
+                    if block.height == 0 && order.get(GENESIS_KEY)?.is_none() {
+                        order.insert(GENESIS_KEY, blockhash.as_bytes())?;
+                    }
+
+                    if tip_height.map_or(true, |height| block.height > height) {
+                        order.insert(TIP_KEY, blockhash.as_bytes())?;
+                        order.insert(TIP_HEIGHT_KEY, &block.height.to_be_bytes())?;
+                        tip_height = Some(block.height);
+                    }
+
+                    for (index, tx) in block.txs.iter().enumerate() {
+                        let tx_hash = blake3::hash(&serialize(tx));
+                        let location = TransactionLocation { block: blockhash, index: index as u32 };
+                        tx_location.insert(tx_hash.as_bytes(), serialize(&location))?;
+                    }
+
+                    ret.push(blockhash);
+                }
+
+                Ok(())
+            });
+
+        txn_result.map_err(|e| {
+            Error::BlockchainError(format!("block insert transaction failed: {}", e))
+        })?;
+
         Ok(ret)
     }
 
+    /// Insert a contiguous slice of ancient [`Block`]s, verifying the chain
+    /// linkage before writing anything. Unlike [`Self::insert`], which
+    /// trusts its input, this checks that each block's `previous_hash`
+    /// matches the hash of the block before it (or the current tip, for the
+    /// first block in the slice), that heights increase by exactly one, and
+    /// that timestamps are monotonically non-decreasing (consecutive blocks
+    /// may share a timestamp, but time may never go backwards). On any
+    /// violation this returns a descriptive `Err` without writing anything,
+    /// so a poisoned chunk streamed from an untrusted peer can be rejected
+    /// atomically.
+    pub fn insert_verified(&self, blocks: &[Block]) -> Result<Vec<blake3::Hash>> {
+        if blocks.is_empty() {
+            return Ok(vec![])
+        }
+
+        let (mut previous_hash, mut previous_height, mut previous_timestamp) = match self.get_tip()?
+        {
+            Some((hash, block)) => (hash, block.height, block.timestamp),
+            None => {
+                return Err(Error::BlockchainError(
+                    "cannot verify an ancient import against an empty blockstore".to_string(),
+                ))
+            }
+        };
+
+        for (i, block) in blocks.iter().enumerate() {
+            if block.previous_hash != previous_hash {
+                return Err(Error::BlockchainError(format!(
+                    "block {} in import has previous_hash {} which does not match expected parent {}",
+                    i, block.previous_hash, previous_hash
+                )))
+            }
+
+            if block.height != previous_height + 1 {
+                return Err(Error::BlockchainError(format!(
+                    "block {} in import has height {}, expected {}",
+                    i,
+                    block.height,
+                    previous_height + 1
+                )))
+            }
+
+            if block.timestamp < previous_timestamp {
+                return Err(Error::BlockchainError(format!(
+                    "block {} in import has a timestamp earlier than its predecessor",
+                    i
+                )))
+            }
+
+            previous_hash = blake3::hash(&serialize(block));
+            previous_height = block.height;
+            previous_timestamp = block.timestamp;
+        }
+
+        self.insert(blocks)
+    }
+
+    /// Stream blocks from `start_height` onward, `chunk_size` at a time,
+    /// reading each chunk from the order tree lazily as it is polled.
+    /// Errors if `chunk_size` is zero, and ends once the height index runs
+    /// dry.
+    pub fn block_stream(
+        &self,
+        start_height: u64,
+        chunk_size: usize,
+    ) -> impl Stream<Item = Result<Vec<Block>>> + '_ {
+        stream! {
+            if chunk_size == 0 {
+                yield Err(Error::BlockchainError(
+                    "block_stream chunk_size must be greater than zero".to_string(),
+                ));
+                return
+            }
+
+            let mut height = start_height;
+
+            loop {
+                let end_height = height + chunk_size as u64 - 1;
+                let chunk = match self.get_range(height, end_height) {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        yield Err(e);
+                        return
+                    }
+                };
+                if chunk.is_empty() {
+                    break
+                }
+
+                let got = chunk.len();
+                yield Ok(chunk.into_iter().map(|(_, block)| block).collect());
+
+                if got < chunk_size {
+                    break
+                }
+
+                height = end_height + 1;
+            }
+        }
+    }
+
+    /// Drive a [`Self::block_stream`] into [`Self::insert_verified`], one
+    /// chunk at a time.
+    pub async fn sync_from_stream(&self, start_height: u64, chunk_size: usize) -> Result<()> {
+        use futures::StreamExt;
+
+        let mut stream = Box::pin(self.block_stream(start_height, chunk_size));
+
+        while let Some(chunk) = stream.next().await {
+            self.insert_verified(&chunk?)?;
+        }
+
+        Ok(())
+    }
+
     /// Fetch given blockhashes from the blockstore.
     /// The resulting vector contains `Option` which is `Some` if the block
     /// was found in the blockstore, and `None`, if it has not.
     pub fn get(&self, blockhashes: &[blake3::Hash]) -> Result<Vec<Option<Block>>> {
-        let mut ret: Vec<Option<Block>> = Vec::with_capacity(blockhashes.len());
+        let mut ret = Vec::with_capacity(blockhashes.len());
 
         for i in blockhashes {
-            if let Some(found) = self.0.get(i.as_bytes())? {
-                let block = deserialize(&found)?;
-                ret.push(Some(block));
-            } else {
-                ret.push(None);
-            }
+            ret.push(self.fetch(i)?);
         }
 
         Ok(ret)
@@ -62,71 +254,400 @@ impl BlockStore {
 
     /// Check if the blockstore contains a given blockhash.
     pub fn contains(&self, blockhash: blake3::Hash) -> Result<bool> {
-        Ok(self.0.contains_key(blockhash.as_bytes())?)
+        Ok(self.main.contains_key(blockhash.as_bytes())?)
     }
 
-    /*
-    /// Fetch the first block in the tree, based on the Ord implementation for Vec<u8>.
-    pub fn get_first(&self) -> Result<Option<(blake3::Hash, Block)>> {
-        if let Some(found) = self.0.first()? {
-            let hash_bytes: [u8; 32] = found.0.as_ref().try_into().unwrap();
-            let block = deserialize(&found.1)?;
-            return Ok(Some((hash_bytes.into(), block)))
+    /// Fetch the block stored at a given height, if one exists.
+    pub fn get_by_height(&self, height: u64) -> Result<Option<Block>> {
+        match self.order.get(height.to_be_bytes())? {
+            Some(found) => self.fetch(&bytes_to_hash(&found)),
+            None => Ok(None),
         }
+    }
 
-        Ok(None)
+    /// Fetch the current best/tip block and its hash, if one exists.
+    pub fn get_tip(&self) -> Result<Option<(blake3::Hash, Block)>> {
+        self.get_pointer(TIP_KEY)
     }
 
-    /// Fetch the last block in the tree, based on the Ord implementation for Vec<u8>.
-    pub fn get_last(&self) -> Result<Option<(blake3::Hash, Block)>> {
-        if let Some(found) = self.0.last()? {
-            let hash_bytes: [u8; 32] = found.0.as_ref().try_into().unwrap();
-            let block = deserialize(&found.1)?;
-            return Ok(Some((hash_bytes.into(), block)))
+    /// Fetch the genesis block and its hash, if one exists.
+    pub fn get_genesis(&self) -> Result<Option<(blake3::Hash, Block)>> {
+        self.get_pointer(GENESIS_KEY)
+    }
+
+    /// Fetch all blocks in the `[start_height, end_height]` range
+    /// (inclusive), ordered by height, by walking the order tree. Unlike
+    /// holding a full range of hashes in memory, this only touches the
+    /// requested window.
+    pub fn get_range(
+        &self,
+        start_height: u64,
+        end_height: u64,
+    ) -> Result<Vec<(blake3::Hash, Block)>> {
+        let mut ret = Vec::new();
+
+        for entry in self.order.range(start_height.to_be_bytes()..=end_height.to_be_bytes()) {
+            let (key, value) = entry?;
+            // The genesis/tip pointers live in this same tree under
+            // non-height keys; skip them defensively even though they sort
+            // well past any realistic height.
+            if key.len() != 8 {
+                continue
+            }
+
+            let blockhash = bytes_to_hash(&value);
+            if let Some(block) = self.fetch(&blockhash)? {
+                ret.push((blockhash, block));
+            }
         }
 
-        Ok(None)
+        Ok(ret)
     }
 
-    /// Fetch the block and its hash before the provided blockhash, if one exists.
-    pub fn get_lt(&self, blockhash: blake3::Hash) -> Result<Option<(blake3::Hash, Block)>> {
-        if let Some(found) = self.0.get_lt(blockhash.as_bytes())? {
-            let hash_bytes: [u8; 32] = found.0.as_ref().try_into().unwrap();
-            let block = deserialize(&found.1)?;
-            return Ok(Some((hash_bytes.into(), block)))
+    /// Fetch the block and hash pointed at by a meta key in the order tree.
+    fn get_pointer(&self, key: &[u8]) -> Result<Option<(blake3::Hash, Block)>> {
+        match self.order.get(key)? {
+            Some(found) => {
+                let blockhash = bytes_to_hash(&found);
+                Ok(self.fetch(&blockhash)?.map(|block| (blockhash, block)))
+            }
+            None => Ok(None),
         }
+    }
 
-        Ok(None)
+    /// Fetch the stored location of a transaction, if it has been indexed.
+    pub fn get_transaction_location(
+        &self,
+        hash: &blake3::Hash,
+    ) -> Result<Option<TransactionLocation>> {
+        match self.tx_location.get(hash.as_bytes())? {
+            Some(found) => Ok(Some(deserialize(&found)?)),
+            None => Ok(None),
+        }
     }
 
-    /// Fetch the block and its hash after the provided blockhash, if one exists.
-    pub fn get_gt(&self, blockhash: blake3::Hash) -> Result<Option<(blake3::Hash, Block)>> {
-        if let Some(found) = self.0.get_gt(blockhash.as_bytes())? {
-            let hash_bytes: [u8; 32] = found.0.as_ref().try_into().unwrap();
-            let block = deserialize(&found.1)?;
-            return Ok(Some((hash_bytes.into(), block)))
+    /// Fetch a transaction by its hash, resolving its location and slicing
+    /// it out of the block that contains it.
+    pub fn get_transaction(&self, hash: &blake3::Hash) -> Result<Option<Transaction>> {
+        let location = match self.get_transaction_location(hash)? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+
+        let block = match self.fetch(&location.block)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+
+        Ok(block.txs.into_iter().nth(location.index as usize))
+    }
+
+    /// Fetch and deserialize a single block by its hash.
+    fn fetch(&self, blockhash: &blake3::Hash) -> Result<Option<Block>> {
+        match self.main.get(blockhash.as_bytes())? {
+            Some(found) => Ok(Some(deserialize(&found)?)),
+            None => Ok(None),
         }
+    }
 
-        Ok(None)
+    /// Compute the [`TreeRoute`] connecting two blocks already in the
+    /// store: the blocks to retract from `from`'s chain and the blocks to
+    /// enact from `to`'s chain in order to switch between them. Used by
+    /// consensus to reorganize onto a heavier fork.
+    ///
+    /// If `from == to`, an empty route with that block as the common
+    /// ancestor is returned straight away, without looking either up.
+    /// Otherwise, returns `Ok(None)` if the store has no genesis block yet,
+    /// and `Err` if either `from` or `to` (or a block on the path between
+    /// them) is missing from the store.
+    pub fn tree_route(&self, from: blake3::Hash, to: blake3::Hash) -> Result<Option<TreeRoute>> {
+        if from == to {
+            return Ok(Some(TreeRoute { common_ancestor: from, retracted: vec![], enacted: vec![] }))
+        }
+
+        let genesis = match self.get_genesis()? {
+            Some((hash, _)) => hash,
+            None => return Ok(None),
+        };
+
+        let mut from_height = self.height_of(&from)?;
+        let mut to_height = self.height_of(&to)?;
+        let mut from_cursor = from;
+        let mut to_cursor = to;
+        let mut retracted = Vec::new();
+        let mut enacted = Vec::new();
+
+        // Walk the deeper side back until both cursors sit at the same
+        // height, recording the blocks that will need to be retracted or
+        // enacted along the way.
+        while from_height > to_height {
+            retracted.push(from_cursor);
+            from_cursor = self.parent_of(&from_cursor)?;
+            from_height -= 1;
+        }
+
+        while to_height > from_height {
+            enacted.push(to_cursor);
+            to_cursor = self.parent_of(&to_cursor)?;
+            to_height -= 1;
+        }
+
+        // Advance both cursors in lockstep until they meet.
+        while from_cursor != to_cursor {
+            if from_cursor == genesis || to_cursor == genesis {
+                // The two chains never meet before genesis, so that's as
+                // far back as we can unwind without looping forever.
+                from_cursor = genesis;
+                to_cursor = genesis;
+                break
+            }
+
+            retracted.push(from_cursor);
+            enacted.push(to_cursor);
+            from_cursor = self.parent_of(&from_cursor)?;
+            to_cursor = self.parent_of(&to_cursor)?;
+        }
+
+        enacted.reverse();
+
+        Ok(Some(TreeRoute { common_ancestor: from_cursor, retracted, enacted }))
+    }
+
+    /// Fetch the height of a stored block, erroring if it is missing.
+    fn height_of(&self, blockhash: &blake3::Hash) -> Result<u64> {
+        match self.fetch(blockhash)? {
+            Some(block) => Ok(block.height),
+            None => Err(Error::BlockchainError(format!("block {} not found in store", blockhash))),
+        }
+    }
+
+    /// Fetch the parent hash of a stored block, erroring if it is missing.
+    fn parent_of(&self, blockhash: &blake3::Hash) -> Result<blake3::Hash> {
+        match self.fetch(blockhash)? {
+            Some(block) => Ok(block.previous_hash),
+            None => Err(Error::BlockchainError(format!("block {} not found in store", blockhash))),
+        }
+    }
+}
+
+/// Describes how to walk from one block in the store to another: the
+/// blocks to retract from the old chain, and the blocks to enact from the
+/// new one, on top of their shared ancestor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeRoute {
+    /// The last block common to both chains.
+    pub common_ancestor: blake3::Hash,
+    /// Blocks to retract, ordered from the current tip down to (but not
+    /// including) the common ancestor.
+    pub retracted: Vec<blake3::Hash>,
+    /// Blocks to enact, ordered from the common ancestor (exclusive) up to
+    /// the new tip.
+    pub enacted: Vec<blake3::Hash>,
+}
+
+/// Convert a raw sled value holding a blockhash back into a [`blake3::Hash`].
+fn bytes_to_hash(bytes: &sled::IVec) -> blake3::Hash {
+    let array: [u8; 32] = bytes.as_ref().try_into().unwrap();
+    array.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a non-genesis block with the given height, parent and
+    /// timestamp, and no transactions.
+    fn block(height: u64, previous_hash: blake3::Hash, timestamp: u64) -> Block {
+        Block { height, previous_hash, timestamp: Timestamp(timestamp), txs: vec![] }
+    }
+
+    /// Build a non-genesis block like [`block`], but carrying the given
+    /// transactions.
+    fn block_with_txs(
+        height: u64,
+        previous_hash: blake3::Hash,
+        timestamp: u64,
+        txs: Vec<Transaction>,
+    ) -> Block {
+        Block { height, previous_hash, timestamp: Timestamp(timestamp), txs }
+    }
+
+    fn new_store() -> BlockStore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        BlockStore::new(&db, Timestamp(0), blake3::hash(b"genesis")).unwrap()
+    }
+
+    #[test]
+    fn tree_route_identical_input_returns_empty_route() {
+        let store = new_store();
+        let (genesis_hash, _) = store.get_genesis().unwrap().unwrap();
+
+        let route = store.tree_route(genesis_hash, genesis_hash).unwrap().unwrap();
+        assert_eq!(route.common_ancestor, genesis_hash);
+        assert!(route.retracted.is_empty());
+        assert!(route.enacted.is_empty());
+    }
+
+    #[test]
+    fn tree_route_missing_block_errors() {
+        let store = new_store();
+        let (genesis_hash, _) = store.get_genesis().unwrap().unwrap();
+        let missing = blake3::hash(b"does-not-exist");
+
+        assert!(store.tree_route(genesis_hash, missing).is_err());
+        assert!(store.tree_route(missing, genesis_hash).is_err());
+    }
+
+    #[test]
+    fn tree_route_forks_off_genesis_meet_at_genesis() {
+        let store = new_store();
+        let (genesis_hash, _) = store.get_genesis().unwrap().unwrap();
+
+        // Two single-block forks off genesis, at the same height, whose
+        // only shared block is genesis itself.
+        let a_hash = store.insert(&[block(1, genesis_hash, 1)]).unwrap()[0];
+        let b_hash = store.insert(&[block(1, genesis_hash, 2)]).unwrap()[0];
+
+        let route = store.tree_route(a_hash, b_hash).unwrap().unwrap();
+        assert_eq!(route.common_ancestor, genesis_hash);
+        assert_eq!(route.retracted, vec![a_hash]);
+        assert_eq!(route.enacted, vec![b_hash]);
+    }
+
+    #[test]
+    fn insert_verified_rejects_wrong_previous_hash() {
+        let store = new_store();
+        let (_, tip) = store.get_tip().unwrap().unwrap();
+        let bogus_parent = blake3::hash(b"not-the-tip");
+
+        let next = block(tip.height + 1, bogus_parent, tip.timestamp.0 + 1);
+        assert!(store.insert_verified(&[next]).is_err());
     }
 
-    /// Retrieve an iterator over a range of blockhashes.
-    /// When iterating, take care of potential memory limitations if you're
-    /// storing results in memory. For blockchain sync, it should probably
-    /// be done in chunks.
-    // Usage:
-    // ```
-    // let mut r = get_range(foo, bar);
-    // while let Some((k, v)) = r.next() {
-    //     let hash_bytes: [u8; 32] = k.as_ref().try_into().unwrap();
-    //     let block = deserialize(&v)?;
-    // }
-    // ```
-    pub fn get_range(&self, start: blake3::Hash, end: blake3::Hash) -> sled::Iter {
-        let start: &[u8] = start.as_bytes().as_ref();
-        let end: &[u8] = end.as_bytes().as_ref();
+    #[test]
+    fn insert_verified_rejects_height_gap() {
+        let store = new_store();
+        let (tip_hash, tip) = store.get_tip().unwrap().unwrap();
+
+        let next = block(tip.height + 2, tip_hash, tip.timestamp.0 + 1);
+        assert!(store.insert_verified(&[next]).is_err());
+    }
+
+    #[test]
+    fn insert_verified_rejects_decreasing_timestamp() {
+        let store = new_store();
+        let (genesis_hash, _) = store.get_genesis().unwrap().unwrap();
+        // `new_store` creates genesis at timestamp 0, which cannot be
+        // undercut, so put a tip with a later timestamp on top of it.
+        store.insert(&[block(1, genesis_hash, 10)]).unwrap();
+        let (tip_hash, tip) = store.get_tip().unwrap().unwrap();
+
+        let next = block(tip.height + 1, tip_hash, tip.timestamp.0 - 1);
+        assert!(store.insert_verified(&[next]).is_err());
+    }
+
+    #[test]
+    fn insert_verified_accepts_equal_consecutive_timestamps() {
+        let store = new_store();
+        let (tip_hash, tip) = store.get_tip().unwrap().unwrap();
+
+        let next = block(tip.height + 1, tip_hash, tip.timestamp.0);
+        assert!(store.insert_verified(&[next]).is_ok());
+    }
+
+    #[test]
+    fn get_by_height_and_get_range_round_trip() {
+        let store = new_store();
+        let (genesis_hash, _) = store.get_genesis().unwrap().unwrap();
+
+        let hashes = store
+            .insert(&[
+                block(1, genesis_hash, 1),
+                block(2, blake3::hash(b"h1"), 2),
+                block(3, blake3::hash(b"h2"), 3),
+            ])
+            .unwrap();
+
+        assert_eq!(store.get_by_height(0).unwrap().unwrap().height, 0);
+        assert_eq!(store.get_by_height(2).unwrap().unwrap().height, 2);
+        assert!(store.get_by_height(99).unwrap().is_none());
+
+        let range = store.get_range(1, 2).unwrap();
+        assert_eq!(range.len(), 2);
+        assert_eq!(range[0].0, hashes[0]);
+        assert_eq!(range[1].0, hashes[1]);
+    }
+
+    #[test]
+    fn get_transaction_and_location_round_trip() {
+        let store = new_store();
+        let (genesis_hash, _) = store.get_genesis().unwrap().unwrap();
+
+        let tx = Transaction::default();
+        let tx_hash = blake3::hash(&serialize(&tx));
+        let block_hash =
+            store.insert(&[block_with_txs(1, genesis_hash, 1, vec![tx.clone()])]).unwrap()[0];
+
+        let location = store.get_transaction_location(&tx_hash).unwrap().unwrap();
+        assert_eq!(location.block, block_hash);
+        assert_eq!(location.index, 0);
+
+        let found = store.get_transaction(&tx_hash).unwrap().unwrap();
+        assert_eq!(serialize(&found), serialize(&tx));
+
+        let missing = blake3::hash(b"no-such-tx");
+        assert!(store.get_transaction_location(&missing).unwrap().is_none());
+        assert!(store.get_transaction(&missing).unwrap().is_none());
+    }
+
+    #[test]
+    fn block_stream_respects_chunk_boundaries() {
+        use futures::StreamExt;
+
+        let store = new_store();
+        let (genesis_hash, _) = store.get_genesis().unwrap().unwrap();
+
+        let mut previous = genesis_hash;
+        for height in 1..=5u64 {
+            previous = store.insert(&[block(height, previous, height)]).unwrap()[0];
+        }
+
+        let chunks: Vec<Result<Vec<Block>>> =
+            futures::executor::block_on(store.block_stream(0, 2).collect());
+        let chunks: Vec<Vec<Block>> = chunks.into_iter().map(|c| c.unwrap()).collect();
+
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 2, 2]);
+        assert_eq!(chunks[0][0].height, 0);
+        assert_eq!(chunks[2][1].height, 5);
+    }
+
+    #[test]
+    fn block_stream_rejects_zero_chunk_size() {
+        use futures::StreamExt;
+
+        let store = new_store();
+
+        let mut results: Vec<Result<Vec<Block>>> =
+            futures::executor::block_on(store.block_stream(0, 0).collect());
+
+        assert_eq!(results.len(), 1);
+        assert!(results.remove(0).is_err());
+    }
+
+    #[test]
+    fn insert_only_advances_tip_to_a_taller_block() {
+        let store = new_store();
+        let (genesis_hash, genesis) = store.get_genesis().unwrap().unwrap();
+
+        let tall_hash = store.insert(&[block(5, genesis_hash, 5)]).unwrap()[0];
+        assert_eq!(store.get_tip().unwrap().unwrap().0, tall_hash);
+
+        // A side-fork block at a lower height must not clobber the tip.
+        store.insert(&[block(1, genesis_hash, 1)]).unwrap();
+        assert_eq!(store.get_tip().unwrap().unwrap().0, tall_hash);
 
-        self.0.range(start..end)
+        // Genesis itself is never mistaken for the tip once a taller block
+        // has been seen.
+        assert_ne!(store.get_tip().unwrap().unwrap().1.height, genesis.height);
     }
-    */
 }